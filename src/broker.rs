@@ -0,0 +1,74 @@
+//! Central event broker. Every per-site poll loop is a pure producer that
+//! pushes `(site, Event)` pairs onto one unbounded channel; this module
+//! owns the receiving end and fans each event out to every sink in turn
+//! (the SSE hub, the SQLite store, the stdout printer, the desktop
+//! notifier). Keeping the sinks out of the poll loop means a slow or
+//! briefly failing one - a desktop notification, say - can never stall the
+//! network loop that feeds it, and a new sink is just another match arm
+//! here instead of a change to every producer.
+
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::mpsc;
+
+use crate::sse::{Hub, SiteEvent};
+use crate::storage::Storage;
+use crate::{EventType, MessageFlag};
+
+pub type Sender = mpsc::UnboundedSender<SiteEvent>;
+pub type Receiver = mpsc::UnboundedReceiver<SiteEvent>;
+
+/// The producer side is cloned into every site task; the consumer side is
+/// handed to `run` once.
+pub fn channel() -> (Sender, Receiver) {
+    mpsc::unbounded_channel()
+}
+
+/// Drain `receiver` until every producer has dropped its `Sender`, fanning
+/// each event out to every sink. Sink errors are logged and never stop the
+/// broker, since one site's bad SQLite write shouldn't cost every other
+/// site its notifications.
+pub async fn run(mut receiver: Receiver, hub: Hub, storage: Arc<Mutex<Storage>>) {
+    while let Some(site_event) = receiver.recv().await {
+        hub.publish(&site_event.site, site_event.event.clone());
+
+        match site_event.event.rest {
+            EventType::Heartbeat => {}
+            EventType::Message { flags, message } => {
+                if let Err(err) = storage
+                    .lock()
+                    .unwrap()
+                    .store_message(&site_event.site, &message)
+                {
+                    eprintln!("{}: failed to store message: {}", site_event.site, err);
+                }
+
+                let is_important = flags.contains(&MessageFlag::Mentioned)
+                    || flags.contains(&MessageFlag::HasAlertWord);
+
+                println!(
+                    "{} {:<20} {}",
+                    if is_important { "!" } else { " " },
+                    site_event.site,
+                    message
+                );
+
+                if is_important {
+                    if let Err(err) = notify_rust::Notification::new()
+                        .summary(&format!("{} {}", site_event.site, message.header()))
+                        .body(&message.content)
+                        .show()
+                    {
+                        eprintln!(
+                            "{}: failed to show desktop notification: {}",
+                            site_event.site, err
+                        );
+                    }
+                }
+            }
+            EventType::Dynamic { event_type, .. } => {
+                println!("{:<20} unhandled event: {}", site_event.site, event_type)
+            }
+        }
+    }
+}
@@ -0,0 +1,88 @@
+//! Crate-wide error type and retry/backoff helper.
+//!
+//! Distinguishing recoverable failures (a dropped connection, a transient
+//! 5xx, a stale event queue) from fatal ones (an expired token) lets the
+//! per-site poll loop retry the former and only give up on the latter,
+//! instead of one bad request tearing down every other site.
+
+use std::time::Duration;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("http request failed: {0}")]
+    Http(#[from] reqwest::Error),
+
+    #[error("failed to parse json response: {0}")]
+    JsonParse(#[from] serde_json::Error),
+
+    #[error("zulip api error {code}: {msg}")]
+    Api { code: String, msg: String },
+
+    #[error("event queue id was rejected by the server")]
+    BadQueueId,
+
+    #[error("local storage error: {0}")]
+    Storage(#[from] rusqlite::Error),
+
+    #[error("{site} configured {len} filters, above its cap of {cap}")]
+    FilterCapExceeded {
+        site: String,
+        len: usize,
+        cap: usize,
+    },
+}
+
+impl Error {
+    /// Whether this failure is worth retrying. Only a truly fatal error
+    /// (e.g. an expired or revoked token) should abort a site's task
+    /// outright; everything else is assumed to be transient.
+    pub fn is_recoverable(&self) -> bool {
+        match self {
+            Error::Http(err) => err.status().is_none_or(|status| status.as_u16() != 401),
+            Error::JsonParse(_) => true,
+            Error::Api { code, .. } => code != "UNAUTHORIZED",
+            Error::BadQueueId => true,
+            Error::Storage(_) => false,
+            Error::FilterCapExceeded { .. } => false,
+        }
+    }
+}
+
+/// Exponential backoff with jitter, reset after every successful attempt.
+pub struct Backoff {
+    attempt: u32,
+    base: Duration,
+    max: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Backoff {
+        Backoff::new()
+    }
+}
+
+impl Backoff {
+    pub fn new() -> Backoff {
+        Backoff {
+            attempt: 0,
+            base: Duration::from_secs(1),
+            max: Duration::from_secs(5 * 60),
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+
+    /// The delay to wait before the next attempt, growing exponentially
+    /// (capped at `max`) and jittered by ±25% so that many sites
+    /// reconnecting at once don't hammer the server in lockstep.
+    pub fn next_delay(&mut self) -> Duration {
+        let exp = self.base * 2u32.saturating_pow(self.attempt.min(16));
+        let capped = exp.min(self.max);
+        self.attempt += 1;
+
+        let jitter = 0.75 + rand::random::<f64>() * 0.5;
+        capped.mul_f64(jitter)
+    }
+}
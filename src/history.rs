@@ -0,0 +1,88 @@
+//! Backfills recent message history on startup (or after re-registering a
+//! queue) so messages sent while the watcher was offline aren't lost, the
+//! way a CHATHISTORY-style replay fills the gap for a reconnecting client.
+
+use std::sync::Arc;
+
+use reqwest::Client;
+
+use crate::error::Error;
+use crate::{ApiResult, Message, NarrowFilter, ZulipSite};
+
+#[derive(Debug, serde::Deserialize)]
+struct GetMessagesResponse {
+    messages: Vec<Message>,
+}
+
+/// The `(anchor, num_before, num_after)` query params for a backfill
+/// request.
+///
+/// The very first backfill has nothing stored yet, so anchor at the
+/// newest message and walk backwards for `count` messages. On every
+/// later run `anchor` is the last message id we actually stored, and
+/// what we want is the gap sent while we were offline, which is *newer*
+/// than that id, so walk forwards instead.
+fn anchor_params(anchor: Option<i64>, count: usize) -> (String, String, String) {
+    match anchor {
+        None => ("newest".to_owned(), count.to_string(), "0".to_owned()),
+        Some(id) => (id.to_string(), "0".to_owned(), count.to_string()),
+    }
+}
+
+/// Fetch up to `count` messages for `site`, anchored after `anchor` (the id
+/// of the newest message we already have stored) or at `"newest"` if this
+/// is the first time we've ever backfilled this site.
+pub async fn backfill(
+    client: &Client,
+    site: &Arc<ZulipSite>,
+    filters: &[NarrowFilter],
+    anchor: Option<i64>,
+    count: usize,
+) -> Result<Vec<Message>, Error> {
+    let (anchor, num_before, num_after) = anchor_params(anchor, count);
+
+    let mut params = vec![
+        ("anchor", anchor),
+        ("num_before", num_before),
+        ("num_after", num_after),
+        ("include_anchor", "false".to_owned()),
+    ];
+    if !filters.is_empty() {
+        params.push(("narrow", serde_json::to_string(filters)?));
+    }
+
+    let mut messages = site
+        .get(client, "messages")
+        .query(&params)
+        .send()
+        .await?
+        .json::<ApiResult<GetMessagesResponse>>()
+        .await?
+        .into_result()?
+        .messages;
+
+    messages.sort_by_key(|message| message.id);
+
+    Ok(messages)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::anchor_params;
+
+    #[test]
+    fn first_backfill_anchors_at_newest_and_walks_backwards() {
+        let (anchor, num_before, num_after) = anchor_params(None, 20);
+        assert_eq!(anchor, "newest");
+        assert_eq!(num_before, "20");
+        assert_eq!(num_after, "0");
+    }
+
+    #[test]
+    fn later_backfill_anchors_at_last_stored_id_and_walks_forwards() {
+        let (anchor, num_before, num_after) = anchor_params(Some(42), 20);
+        assert_eq!(anchor, "42");
+        assert_eq!(num_before, "0");
+        assert_eq!(num_after, "20");
+    }
+}
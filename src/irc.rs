@@ -0,0 +1,374 @@
+//! A minimal IRC server projection over the watched Zulip sites: streams
+//! map to channels (`#site:stream`), private conversations to query
+//! windows, and `PRIVMSG`s in either direction are bridged through the
+//! already-present `ZulipSite::post` helper. Just enough of the protocol
+//! (`PASS`/`NICK`/`USER`/`JOIN`/`PRIVMSG`/`NAMES`) is implemented for a
+//! plain IRC client to read along and reply.
+//!
+//! A connected client can read every DM and post as the bridged Zulip
+//! account, so `PASS` against the configured `irc_password` is mandatory
+//! and checked before anything else (including `NICK`/`USER`) is honored.
+
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use reqwest::Client;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+use tokio::sync::broadcast;
+
+use crate::sse::{Hub, SiteEvent};
+use crate::storage::Storage;
+use crate::{EventType, MessageRecipients, ZulipSite};
+
+/// How many past messages to replay when a client joins a channel, the
+/// IRC equivalent of a CHATHISTORY request.
+const REPLAY_COUNT: usize = 20;
+
+/// Default topic used for stream messages sent from IRC, since the IRC
+/// protocol this gateway speaks has no notion of Zulip topics.
+const DEFAULT_TOPIC: &str = "irc";
+
+/// How often the gateway pings an idle client. Real IRC clients treat a
+/// server that never pings (or never answers a `PING`) as dead and
+/// disconnect after their own lag timeout, so both directions matter here.
+const PING_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Everything a client handler needs to talk to the rest of the process,
+/// grouped so it's one clone (and one argument) per connection instead of
+/// four.
+#[derive(Clone)]
+struct Gateway {
+    client: Client,
+    sites: HashMap<String, Arc<ZulipSite>>,
+    storage: Arc<Mutex<Storage>>,
+    password: Arc<String>,
+}
+
+pub async fn serve(
+    addr: SocketAddr,
+    password: String,
+    client: Client,
+    sites: Vec<Arc<ZulipSite>>,
+    storage: Arc<Mutex<Storage>>,
+    hub: Hub,
+) -> std::io::Result<()> {
+    let gateway = Gateway {
+        client,
+        sites: sites.into_iter().map(|site| (site.name.clone(), site)).collect(),
+        storage,
+        password: Arc::new(password),
+    };
+
+    let listener = TcpListener::bind(addr).await?;
+    println!("irc gateway listening on {}", addr);
+
+    loop {
+        let (socket, _) = listener.accept().await?;
+        let gateway = gateway.clone();
+        let events = hub.subscribe();
+
+        tokio::spawn(async move {
+            if let Err(err) = handle_client(socket, gateway, events).await {
+                eprintln!("irc client disconnected: {}", err);
+            }
+        });
+    }
+}
+
+/// A nickname as it should appear on the wire, Zulip full names containing
+/// spaces IRC clients wouldn't parse as a single nick.
+fn irc_nick(full_name: &str) -> String {
+    full_name.replace(' ', "_")
+}
+
+/// `#site:stream` <-> `(site, stream)`. Colon-separated so site or stream
+/// names containing a hyphen aren't ambiguous.
+fn parse_channel(channel: &str) -> Option<(&str, &str)> {
+    channel.strip_prefix('#')?.split_once(':')
+}
+
+/// Send `RPL_NAMREPLY`/`RPL_ENDOFNAMES` for `channel`. The gateway doesn't
+/// track who else is bridged into a channel, so the only member it can
+/// honestly list is the requesting client itself, and only once it's
+/// actually joined (`list_members`) rather than just asked about it.
+async fn send_names_reply(
+    writer: &mut (impl AsyncWriteExt + Unpin),
+    nick: &str,
+    channel: &str,
+    list_members: bool,
+) -> std::io::Result<()> {
+    if list_members {
+        writer
+            .write_all(format!(":bridge 353 {nick} = {channel} :{nick}\r\n").as_bytes())
+            .await?;
+    }
+    writer
+        .write_all(format!(":bridge 366 {nick} {channel} :End of /NAMES list\r\n").as_bytes())
+        .await?;
+    Ok(())
+}
+
+/// Per-connection state that accumulates as a client speaks the protocol.
+#[derive(Default)]
+struct ClientState {
+    nick: String,
+    joined: HashSet<String>,
+    authenticated: bool,
+}
+
+async fn handle_client(
+    socket: tokio::net::TcpStream,
+    gateway: Gateway,
+    mut events: broadcast::Receiver<SiteEvent>,
+) -> std::io::Result<()> {
+    let (reader, mut writer) = socket.into_split();
+    let mut lines = BufReader::new(reader).lines();
+
+    let mut state = ClientState::default();
+    let mut ping_interval = tokio::time::interval(PING_INTERVAL);
+
+    loop {
+        tokio::select! {
+            _ = ping_interval.tick() => {
+                writer.write_all(b"PING :bridge\r\n").await?;
+            }
+            line = lines.next_line() => {
+                let Some(line) = line? else { break };
+                handle_line(&line, &mut state, &gateway, &mut writer).await?;
+            }
+            event = events.recv() => {
+                match event {
+                    // Same PASS gate as handle_line.
+                    Ok(site_event) if state.authenticated => {
+                        forward_event(&site_event, &state.nick, &state.joined, &mut writer).await?
+                    }
+                    Ok(_) => {}
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn forward_event(
+    site_event: &SiteEvent,
+    nick: &str,
+    joined: &HashSet<String>,
+    writer: &mut (impl AsyncWriteExt + Unpin),
+) -> std::io::Result<()> {
+    let EventType::Message { message, .. } = &site_event.event.rest else {
+        return Ok(());
+    };
+
+    let from = irc_nick(&message.sender_full_name);
+    match &message.display_recipient {
+        MessageRecipients::Stream(stream) => {
+            let channel = format!("#{}:{}", site_event.site, stream);
+            if joined.contains(&channel) {
+                send_privmsg(writer, &from, &channel, &message.content).await?;
+            }
+        }
+        MessageRecipients::Users(_) => {
+            // A private message always gets delivered, the way an
+            // incoming PRIVMSG from a new nick opens a query window
+            // client-side without needing a JOIN first.
+            send_privmsg(writer, &from, nick, &message.content).await?;
+        }
+    }
+
+    Ok(())
+}
+
+async fn send_privmsg(
+    writer: &mut (impl AsyncWriteExt + Unpin),
+    from: &str,
+    target: &str,
+    content: &str,
+) -> std::io::Result<()> {
+    // `.lines()` only splits on `\n`/`\r\n`, leaving a bare `\r` embedded in
+    // the emitted line; content comes straight from Zulip messages, so
+    // split on both and drop what that empties out instead of trusting it.
+    for line in content.split(['\r', '\n']).filter(|line| !line.is_empty()) {
+        writer
+            .write_all(format!(":{}!zulip@bridge PRIVMSG {} :{}\r\n", from, target, line).as_bytes())
+            .await?;
+    }
+    Ok(())
+}
+
+async fn handle_line(
+    line: &str,
+    state: &mut ClientState,
+    gateway: &Gateway,
+    writer: &mut (impl AsyncWriteExt + Unpin),
+) -> std::io::Result<()> {
+    let mut parts = line.splitn(2, ' ');
+    let command = parts.next().unwrap_or("").to_uppercase();
+    let rest = parts.next().unwrap_or("");
+
+    // See the module docs: PASS gates everything else.
+    if command != "PASS" && !state.authenticated {
+        writer
+            .write_all(b":bridge 464 * :Password required, send PASS first\r\n")
+            .await?;
+        return Ok(());
+    }
+
+    match command.as_str() {
+        "PASS" => {
+            state.authenticated = rest.trim() == gateway.password.as_str();
+            if !state.authenticated {
+                writer
+                    .write_all(b":bridge 464 * :Password incorrect\r\n")
+                    .await?;
+            }
+        }
+        "PING" => {
+            writer
+                .write_all(format!(":bridge PONG bridge :{}\r\n", rest).as_bytes())
+                .await?;
+        }
+        "NICK" => {
+            state.nick = rest.trim().to_owned();
+        }
+        "USER" => {
+            let nick = &state.nick;
+            writer
+                .write_all(
+                    format!(
+                        ":bridge 001 {nick} :Welcome to the Zulip IRC gateway\r\n\
+                         :bridge 376 {nick} :End of /MOTD command\r\n",
+                        nick = nick
+                    )
+                    .as_bytes(),
+                )
+                .await?;
+        }
+        "JOIN" => {
+            let channel = rest.trim().to_owned();
+            let Some((site_name, stream)) = parse_channel(&channel) else {
+                writer
+                    .write_all(format!(":bridge 403 {} :No such channel\r\n", channel).as_bytes())
+                    .await?;
+                return Ok(());
+            };
+
+            if !gateway.sites.contains_key(site_name) {
+                writer
+                    .write_all(format!(":bridge 403 {} :No such site\r\n", channel).as_bytes())
+                    .await?;
+                return Ok(());
+            }
+
+            state.joined.insert(channel.clone());
+            writer
+                .write_all(
+                    format!(":{}!zulip@bridge JOIN {}\r\n", state.nick, channel).as_bytes(),
+                )
+                .await?;
+
+            let history = gateway
+                .storage
+                .lock()
+                .unwrap()
+                .recent_for_recipient(site_name, &format!("#{}", stream), REPLAY_COUNT)
+                .unwrap_or_default();
+            for message in history {
+                send_privmsg(
+                    writer,
+                    &irc_nick(&message.sender_full_name),
+                    &channel,
+                    &message.content,
+                )
+                .await?;
+            }
+
+            send_names_reply(writer, &state.nick, &channel, true).await?;
+        }
+        "NAMES" => {
+            let channel = rest.trim();
+            let joined = state.joined.contains(channel);
+            send_names_reply(writer, &state.nick, channel, joined).await?;
+        }
+        "PRIVMSG" => {
+            let mut target_and_text = rest.splitn(2, " :");
+            let target = target_and_text.next().unwrap_or("").trim();
+            let text = target_and_text.next().unwrap_or("");
+
+            if let Some((site_name, stream)) = parse_channel(target) {
+                if let Some(site) = gateway.sites.get(site_name) {
+                    match site
+                        .post(&gateway.client, "messages")
+                        .form(&[
+                            ("type", "stream"),
+                            ("to", stream),
+                            ("topic", DEFAULT_TOPIC),
+                            ("content", text),
+                        ])
+                        .send()
+                        .await
+                    {
+                        Ok(resp) if resp.status().is_success() => {}
+                        Ok(resp) => {
+                            eprintln!("{}: failed to post message: HTTP {}", site_name, resp.status());
+                            writer
+                                .write_all(
+                                    format!(
+                                        ":bridge NOTICE {} :message to {} not delivered: zulip \
+                                         returned {}\r\n",
+                                        state.nick,
+                                        target,
+                                        resp.status()
+                                    )
+                                    .as_bytes(),
+                                )
+                                .await?;
+                        }
+                        Err(err) => {
+                            eprintln!("{}: failed to post message: {}", site_name, err);
+                            writer
+                                .write_all(
+                                    format!(
+                                        ":bridge NOTICE {} :message to {} not delivered: {}\r\n",
+                                        state.nick, target, err
+                                    )
+                                    .as_bytes(),
+                                )
+                                .await?;
+                        }
+                    }
+                } else {
+                    writer
+                        .write_all(
+                            format!(":bridge 403 {} :No such site\r\n", target).as_bytes(),
+                        )
+                        .await?;
+                }
+            } else {
+                // Replying in a DM's query window isn't wired up yet: a
+                // bare nick carries no Zulip user id/email to post to, only
+                // the display name `forward_event` rendered it with. Tell
+                // the client instead of swallowing the message.
+                writer
+                    .write_all(
+                        format!(
+                            ":bridge 401 {} {} :No such nick - private replies aren't \
+                             supported, reply in the site's channel instead\r\n",
+                            state.nick, target
+                        )
+                        .as_bytes(),
+                    )
+                    .await?;
+            }
+        }
+        _ => {}
+    }
+
+    Ok(())
+}
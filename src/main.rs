@@ -1,10 +1,60 @@
-use std::{collections::HashMap, error::Error, fmt, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    sync::{Arc, Mutex},
+};
 
 use reqwest::Client;
 
+mod broker;
+mod error;
+mod history;
+mod irc;
+mod sse;
+mod storage;
+
+use error::{Backoff, Error};
+use sse::SiteEvent;
+
 #[derive(Debug, serde::Deserialize)]
 struct Config {
     sites: Vec<ZulipSite>,
+    #[serde(default = "default_sse_addr")]
+    sse_addr: std::net::SocketAddr,
+    /// Shared secret a client must pass as `?token=` on `/events`. Required
+    /// whenever `sse_addr` is bound to anything but loopback: that endpoint
+    /// broadcasts every watched message, including DMs, to anyone who can
+    /// reach it, the same exposure `irc_password` guards against below.
+    #[serde(default)]
+    sse_token: Option<String>,
+    /// How many messages to backfill per site on startup / reconnect.
+    #[serde(default = "default_history_count")]
+    history_count: usize,
+    /// Where the SQLite store for backfilled and streamed messages lives.
+    #[serde(default = "default_db_path")]
+    db_path: std::path::PathBuf,
+    /// If set, run an IRC gateway on this address so any IRC client can
+    /// read and reply to the watched sites. Disabled by default.
+    #[serde(default)]
+    irc_addr: Option<std::net::SocketAddr>,
+    /// Shared secret an IRC client must send via `PASS` before the gateway
+    /// accepts anything else from it. Required whenever `irc_addr` is set:
+    /// a connected client can read every DM and post as the bridged Zulip
+    /// account, so there is no unauthenticated mode.
+    #[serde(default)]
+    irc_password: Option<String>,
+}
+
+fn default_sse_addr() -> std::net::SocketAddr {
+    ([127, 0, 0, 1], 8080).into()
+}
+
+fn default_history_count() -> usize {
+    100
+}
+
+fn default_db_path() -> std::path::PathBuf {
+    std::path::PathBuf::from("zulip-messages.sqlite3")
 }
 
 #[derive(Clone, Debug, serde::Deserialize)]
@@ -12,6 +62,29 @@ struct ZulipSite {
     name: String,
     user: String,
     token: String,
+
+    /// Zulip narrow operators (`stream`, `topic`, `sender`, `is:mentioned`,
+    /// ...) restricting which conversations this site's event queue
+    /// delivers. An empty list behaves like today: every message the user
+    /// can see.
+    #[serde(default)]
+    filters: Vec<NarrowFilter>,
+
+    /// Upper bound on `filters.len()`, so a typo'd config can't register a
+    /// queue with an unbounded narrow.
+    #[serde(default = "default_filter_cap")]
+    filter_cap: usize,
+}
+
+fn default_filter_cap() -> usize {
+    20
+}
+
+/// A single Zulip narrow operator, e.g. `{"operator": "stream", "operand": "announcements"}`.
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+struct NarrowFilter {
+    operator: String,
+    operand: String,
 }
 
 impl ZulipSite {
@@ -21,13 +94,13 @@ impl ZulipSite {
 
     fn get(&self, client: &Client, api: &str) -> reqwest::RequestBuilder {
         client
-            .get(&self.api_url(api))
+            .get(self.api_url(api))
             .basic_auth(&self.user, Some(&self.token))
     }
 
     fn post(&self, client: &Client, api: &str) -> reqwest::RequestBuilder {
         client
-            .post(&self.api_url(api))
+            .post(self.api_url(api))
             .basic_auth(&self.user, Some(&self.token))
     }
 }
@@ -42,69 +115,175 @@ enum ApiResult<T> {
 }
 
 impl<T> ApiResult<T> {
-    fn into_result(self) -> Result<T, Box<dyn std::error::Error + Send + Sync>> {
+    fn into_result(self) -> Result<T, Error> {
         match self {
             ApiResult::Success(val) => Ok(val),
-            ApiResult::Error(err) => Err(format!("api call failed: {:?}", err).into()),
+            ApiResult::Error(mut err) => {
+                let code = err
+                    .remove("code")
+                    .and_then(|v| v.as_str().map(str::to_owned))
+                    .unwrap_or_else(|| "UNKNOWN".to_owned());
+                let msg = err
+                    .remove("msg")
+                    .and_then(|v| v.as_str().map(str::to_owned))
+                    .unwrap_or_else(|| format!("{:?}", err));
+                Err(Error::Api { code, msg })
+            }
         }
     }
 }
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
+async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let config: Config = serde_json::de::from_str(&std::fs::read_to_string("config.json")?)?;
 
-    let res = futures::future::try_join_all(config.sites.into_iter().map(|site| {
-        tokio::spawn(async move {
-            let site = Arc::new(site);
-            println!("watching {}", site.name);
+    if !config.sse_addr.ip().is_loopback() && config.sse_token.is_none() {
+        return Err("sse_addr is not bound to loopback but sse_token is not set: /events \
+                     broadcasts every watched message, including DMs, to anyone who can reach \
+                     it, so a shared secret is required"
+            .into());
+    }
 
-            let client = Client::builder()
-                .user_agent("zulip client by @bjorn3")
-                .build()?;
+    let hub = sse::Hub::new();
+    tokio::spawn({
+        let hub = hub.clone();
+        let sse_addr = config.sse_addr;
+        let sse_token = config.sse_token.clone();
+        async move { hub.serve(sse_addr, sse_token).await }
+    });
+
+    let storage = Arc::new(Mutex::new(storage::Storage::open(&config.db_path)?));
+    let history_count = config.history_count;
+    let sites: Vec<Arc<ZulipSite>> = config.sites.into_iter().map(Arc::new).collect();
+
+    if let Some(irc_addr) = config.irc_addr {
+        let irc_password = config.irc_password.ok_or(
+            "irc_addr is set but irc_password is not: the gateway exposes every DM and lets \
+             anyone who can reach it post as the bridged Zulip account, so a shared secret is \
+             required",
+        )?;
+        let client = Client::builder()
+            .user_agent("zulip client by @bjorn3")
+            .build()?;
+        let irc_sites = sites.clone();
+        let hub = hub.clone();
+        let storage = storage.clone();
+        tokio::spawn(async move {
+            if let Err(err) =
+                irc::serve(irc_addr, irc_password, client, irc_sites, storage, hub).await
+            {
+                eprintln!("irc gateway stopped: {}", err);
+            }
+        });
+    }
 
-            let mut queue = EventQueue::register_new(&client, site.clone()).await?;
+    // Every site's poll loop only ever produces onto this channel; the
+    // broker task owns the receiving end and is the only thing that talks
+    // to the SSE hub, the SQLite store, stdout, or the desktop notifier.
+    let (event_tx, event_rx) = broker::channel();
+    tokio::spawn({
+        let hub = hub.clone();
+        let storage = storage.clone();
+        async move { broker::run(event_rx, hub, storage).await }
+    });
+
+    // join_all, not try_join_all: see error::Error::is_recoverable for why
+    // a fatal error from one site must only end that site's task.
+    futures::future::join_all(sites.into_iter().map(|site| {
+        let storage = storage.clone();
+        let event_tx = event_tx.clone();
+        tokio::spawn(async move {
+            println!("watching {}", site.name);
 
-            println!("queue for {}: {}", site.name, queue.queue_id.0);
+            let client = match Client::builder().user_agent("zulip client by @bjorn3").build() {
+                Ok(client) => client,
+                Err(err) => {
+                    eprintln!("{}: fatal error: {}", site.name, err);
+                    return;
+                }
+            };
 
+            // Retry recoverable failures with backoff; give up on this
+            // site only once `run_site` returns a fatal error.
+            let mut backoff = Backoff::new();
             loop {
-                let events = queue.long_poll(&client).await?;
-                for event in events {
-                    match event.rest {
-                        EventType::Heartbeat => {}
-                        EventType::Message { flags, message } => {
-                            let is_important = flags.contains(&MessageFlag::Mentioned)
-                                || flags.contains(&MessageFlag::HasAlertWord);
-
-                            println!(
-                                "{} {:<20} {}",
-                                if is_important { "!" } else { " " },
-                                site.name,
-                                message
-                            );
-
-                            if is_important {
-                                notify_rust::Notification::new()
-                                    .summary(&format!("{} {}", site.name, message.header()))
-                                    .body(&message.content)
-                                    .show()?;
-                            }
-                        }
-                        EventType::Other => println!("unknown event"),
+                match run_site(
+                    &client,
+                    site.clone(),
+                    event_tx.clone(),
+                    &storage,
+                    history_count,
+                    &mut backoff,
+                )
+                .await
+                {
+                    Err(err) if err.is_recoverable() => {
+                        let delay = backoff.next_delay();
+                        eprintln!("{}: {} (retrying in {:?})", site.name, err, delay);
+                        tokio::time::sleep(delay).await;
                     }
+                    Err(err) => {
+                        eprintln!("{}: fatal error, giving up on this site: {}", site.name, err);
+                        return;
+                    }
+                    Ok(()) => unreachable!("run_site only returns once an error occurs"),
                 }
             }
-
-            Ok::<(), Box<dyn Error + Send + Sync>>(())
         })
     }))
     .await;
 
-    println!("{:?}", res);
-
     Ok(())
 }
 
+/// Register a queue, backfill recent history, then poll forever, pushing
+/// every live event onto `event_tx` for the broker to fan out. Returns
+/// only when an error (recoverable or not) interrupts the loop; the caller
+/// decides whether to retry.
+async fn run_site(
+    client: &Client,
+    site: Arc<ZulipSite>,
+    event_tx: broker::Sender,
+    storage: &Mutex<storage::Storage>,
+    history_count: usize,
+    backoff: &mut Backoff,
+) -> Result<(), Error> {
+    let mut queue = EventQueue::register_new(client, site.clone()).await?;
+
+    println!("queue for {}: {}", site.name, queue.queue_id.0);
+
+    let anchor = storage.lock().unwrap().last_anchor(&site.name)?;
+    let backfilled =
+        history::backfill(client, &site, &site.filters, anchor, history_count).await?;
+
+    let mut seen_history_ids = HashSet::new();
+    for message in &backfilled {
+        storage.lock().unwrap().store_message(&site.name, message)?;
+        seen_history_ids.insert(message.id);
+        println!("{} (history) {}", site.name, message);
+    }
+
+    loop {
+        let events = queue.long_poll(client).await?;
+        backoff.reset();
+        for event in events {
+            // A message already seen during backfill was already stored
+            // and printed above; don't hand it to the broker a second
+            // time.
+            if let EventType::Message { ref message, .. } = event.rest {
+                if seen_history_ids.remove(&message.id) {
+                    continue;
+                }
+            }
+
+            let _ = event_tx.send(SiteEvent {
+                site: site.name.clone(),
+                event,
+            });
+        }
+    }
+}
+
 #[derive(Debug, serde::Deserialize)]
 #[serde(transparent)]
 struct EventQueueId(String);
@@ -127,14 +306,14 @@ struct PollEventQueue {
     events: Vec<Event>,
 }
 
-#[derive(Debug, serde::Deserialize)]
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
 struct Event {
     id: i64,
     #[serde(flatten)]
     rest: EventType,
 }
 
-#[derive(Debug, serde::Deserialize)]
+#[derive(Clone, Debug, serde::Serialize)]
 #[serde(tag = "type")]
 #[serde(rename_all = "snake_case")]
 enum EventType {
@@ -145,15 +324,58 @@ enum EventType {
         message: Message,
     },
 
-    #[serde(other)]
-    Other,
+    /// Every event type Zulip defines that we don't have a dedicated variant
+    /// for yet (reactions, subscriptions, presence, typing, ...). The raw
+    /// event name is kept alongside the untouched JSON body so callers can
+    /// still inspect, log, or route it instead of the event being silently
+    /// discarded.
+    Dynamic {
+        event_type: String,
+        payload: serde_json::Value,
+    },
 }
 
 #[derive(Debug, serde::Deserialize)]
+struct MessageEventFields {
+    flags: Vec<MessageFlag>,
+    message: Message,
+}
+
+impl<'de> serde::Deserialize<'de> for EventType {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let payload = serde_json::Value::deserialize(deserializer)?;
+        let event_type = payload
+            .get("type")
+            .and_then(serde_json::Value::as_str)
+            .ok_or_else(|| serde::de::Error::missing_field("type"))?
+            .to_owned();
+
+        match event_type.as_str() {
+            "heartbeat" => Ok(EventType::Heartbeat),
+            "message" => {
+                let fields: MessageEventFields =
+                    serde_json::from_value(payload).map_err(serde::de::Error::custom)?;
+                Ok(EventType::Message {
+                    flags: fields.flags,
+                    message: fields.message,
+                })
+            }
+            _ => Ok(EventType::Dynamic {
+                event_type,
+                payload,
+            }),
+        }
+    }
+}
+
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
 struct Message {
     content: String,
     display_recipient: MessageRecipients,
-    //id: u64,
+    id: i64,
     //reactions: Vec<serde_json::Value>,
     sender_full_name: String,
     //stream_id: Option<u64>,
@@ -180,7 +402,7 @@ impl fmt::Display for Message {
     }
 }
 
-#[derive(Debug, Eq, PartialEq, serde::Deserialize)]
+#[derive(Clone, Debug, Eq, PartialEq, serde::Deserialize, serde::Serialize)]
 #[serde(rename_all = "snake_case")]
 enum MessageFlag {
     Read,
@@ -188,7 +410,7 @@ enum MessageFlag {
     HasAlertWord,
 }
 
-#[derive(Debug, serde::Deserialize)]
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
 #[serde(untagged)]
 enum MessageRecipients {
     Stream(String),
@@ -215,7 +437,7 @@ impl fmt::Display for MessageRecipients {
     }
 }
 
-#[derive(Debug, serde::Deserialize)]
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
 struct User {
     full_name: String,
 }
@@ -226,23 +448,30 @@ impl fmt::Display for User {
     }
 }
 
-#[derive(Debug, serde::Deserialize)]
-#[serde(rename_all = "snake_case")]
-enum MessageType {
-    Stream,
-    Private,
-}
-
 impl EventQueue {
     async fn register_new(
         client: &Client,
         site: Arc<ZulipSite>,
-    ) -> Result<EventQueue, Box<dyn Error + Send + Sync>> {
+    ) -> Result<EventQueue, Error> {
+        if site.filters.len() > site.filter_cap {
+            return Err(Error::FilterCapExceeded {
+                site: site.name.clone(),
+                len: site.filters.len(),
+                cap: site.filter_cap,
+            });
+        }
+
+        let mut params = vec![
+            ("event_types", serde_json::to_string(&["message"])?),
+            ("all_public_streams", "false".to_owned()),
+        ];
+        if !site.filters.is_empty() {
+            params.push(("narrow", serde_json::to_string(&site.filters)?));
+        }
+
         let register_resp = site
-            .post(
-                client,
-                "register?event_types=%5B%22message%22%5D&all_public_streams=false",
-            )
+            .post(client, "register")
+            .query(&params)
             .send()
             .await?
             .json::<ApiResult<RegisterEventQueue>>()
@@ -256,10 +485,7 @@ impl EventQueue {
         })
     }
 
-    async fn long_poll(
-        &mut self,
-        client: &Client,
-    ) -> Result<Vec<Event>, Box<dyn std::error::Error + Send + Sync>> {
+    async fn long_poll(&mut self, client: &Client) -> Result<Vec<Event>, Error> {
         let poll_resp = self
             .site
             .get(
@@ -278,8 +504,7 @@ impl EventQueue {
             ApiResult::Error(err)
                 if err.get("code").and_then(|code| code.as_str()) == Some("BAD_EVENT_QUEUE_ID") =>
             {
-                *self = Self::register_new(client, self.site.clone()).await?;
-                return Ok(vec![]);
+                return Err(Error::BadQueueId);
             }
             poll_resp => poll_resp.into_result()?,
         };
@@ -293,3 +518,51 @@ impl EventQueue {
         Ok(poll_resp.events)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::EventType;
+
+    #[test]
+    fn deserializes_heartbeat_event() {
+        let event: EventType = serde_json::from_str(r#"{"type": "heartbeat"}"#).unwrap();
+        assert!(matches!(event, EventType::Heartbeat));
+    }
+
+    #[test]
+    fn deserializes_message_event() {
+        let event: EventType = serde_json::from_str(
+            r#"{
+                "type": "message",
+                "flags": ["read"],
+                "message": {
+                    "content": "hi",
+                    "display_recipient": "general",
+                    "id": 1,
+                    "sender_full_name": "Alice",
+                    "timestamp": 1700000000,
+                    "type": "stream"
+                }
+            }"#,
+        )
+        .unwrap();
+
+        let EventType::Message { flags, message } = event else {
+            panic!("expected EventType::Message, got {:?}", event);
+        };
+        assert_eq!(flags, vec![super::MessageFlag::Read]);
+        assert_eq!(message.content, "hi");
+    }
+
+    #[test]
+    fn falls_back_to_dynamic_for_unknown_event_types() {
+        let event: EventType =
+            serde_json::from_str(r#"{"type": "reaction", "emoji": "tada"}"#).unwrap();
+
+        let EventType::Dynamic { event_type, payload } = event else {
+            panic!("expected EventType::Dynamic, got {:?}", event);
+        };
+        assert_eq!(event_type, "reaction");
+        assert_eq!(payload["emoji"], "tada");
+    }
+}
@@ -0,0 +1,98 @@
+//! Re-broadcasts every watched `Event` to local subscribers over
+//! Server-Sent Events, so other tools can follow the live stream without
+//! registering their own Zulip event queue.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+use futures::StreamExt;
+use tokio::sync::broadcast;
+use warp::{Filter, Reply};
+
+use crate::Event;
+
+/// An `Event` tagged with the site it came from, the unit that actually
+/// goes out over the wire to SSE subscribers.
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct SiteEvent {
+    pub site: String,
+    pub event: Event,
+}
+
+/// How many in-flight events each subscriber can lag behind before it starts
+/// missing them. Matches the default most `tokio::sync::broadcast` users
+/// pick for a chatty-but-bounded stream.
+const CHANNEL_CAPACITY: usize = 1024;
+
+/// Central hub that per-site poll loops publish into and that the `/events`
+/// HTTP endpoint subscribes from. Cloning a `Hub` is cheap; every clone
+/// shares the same underlying broadcast channel.
+#[derive(Clone)]
+pub struct Hub {
+    sender: broadcast::Sender<SiteEvent>,
+}
+
+impl Default for Hub {
+    fn default() -> Self {
+        Hub::new()
+    }
+}
+
+impl Hub {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(CHANNEL_CAPACITY);
+        Hub { sender }
+    }
+
+    /// Publish an event for `site` to every currently connected subscriber.
+    /// Returns without error even if nobody is listening.
+    pub fn publish(&self, site: &str, event: Event) {
+        let _ = self.sender.send(SiteEvent {
+            site: site.to_owned(),
+            event,
+        });
+    }
+
+    /// Subscribe to the live event stream, e.g. for a consumer other than
+    /// the SSE endpoint (the IRC gateway).
+    pub fn subscribe(&self) -> broadcast::Receiver<SiteEvent> {
+        self.sender.subscribe()
+    }
+
+    /// Serve the `/events` SSE endpoint on `addr` until the process exits.
+    ///
+    /// `token`, if set, must be echoed back as `?token=` on every request;
+    /// a missing or wrong token gets a `401` instead of the stream. Callers
+    /// are expected to require this whenever `addr` isn't loopback, since
+    /// the stream it serves includes every watched message, DMs included.
+    pub async fn serve(&self, addr: SocketAddr, token: Option<String>) {
+        let sender = self.sender.clone();
+        let events = warp::path("events")
+            .and(warp::get())
+            .and(warp::query::<HashMap<String, String>>())
+            .map(move |query: HashMap<String, String>| {
+                if let Some(expected) = &token {
+                    if query.get("token") != Some(expected) {
+                        return warp::reply::with_status(
+                            "unauthorized",
+                            warp::http::StatusCode::UNAUTHORIZED,
+                        )
+                        .into_response();
+                    }
+                }
+
+                let receiver = sender.subscribe();
+                let stream = tokio_stream::wrappers::BroadcastStream::new(receiver)
+                    .filter_map(|event| async move { event.ok() })
+                    .map(|event| {
+                        let json = serde_json::to_string(&event)
+                            .unwrap_or_else(|_| "null".to_owned());
+                        Ok::<_, std::convert::Infallible>(warp::sse::Event::default().data(json))
+                    });
+
+                warp::sse::reply(warp::sse::keep_alive().stream(stream)).into_response()
+            });
+
+        warp::serve(events).run(addr).await;
+    }
+}
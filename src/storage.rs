@@ -0,0 +1,78 @@
+//! A small SQLite-backed store for messages we've seen, either through
+//! history backfill or the live event queue, so a restart can resume from
+//! the last message we actually stored instead of from "now".
+
+use std::path::Path;
+
+use crate::Message;
+
+pub struct Storage {
+    conn: rusqlite::Connection,
+}
+
+impl Storage {
+    pub fn open(path: &Path) -> rusqlite::Result<Storage> {
+        let conn = rusqlite::Connection::open(path)?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS messages (
+                site TEXT NOT NULL,
+                message_id INTEGER NOT NULL,
+                recipient TEXT NOT NULL,
+                payload TEXT NOT NULL,
+                PRIMARY KEY (site, message_id)
+            )",
+            [],
+        )?;
+        Ok(Storage { conn })
+    }
+
+    /// Store `message` for `site`, overwriting any earlier copy with the
+    /// same id (e.g. if it was edited between backfill and live delivery).
+    pub fn store_message(&self, site: &str, message: &Message) -> rusqlite::Result<()> {
+        let payload = serde_json::to_string(message).expect("Message always serializes");
+        let recipient = message.display_recipient.to_string();
+        self.conn.execute(
+            "INSERT OR REPLACE INTO messages (site, message_id, recipient, payload)
+             VALUES (?1, ?2, ?3, ?4)",
+            rusqlite::params![site, message.id, recipient, payload],
+        )?;
+        Ok(())
+    }
+
+    /// The highest message id we've stored for `site`, used as the anchor
+    /// for the next backfill so restarts don't lose or re-show history.
+    pub fn last_anchor(&self, site: &str) -> rusqlite::Result<Option<i64>> {
+        self.conn.query_row(
+            "SELECT MAX(message_id) FROM messages WHERE site = ?1",
+            rusqlite::params![site],
+            |row| row.get(0),
+        )
+    }
+
+    /// The most recent `limit` messages stored for `site` and `recipient`
+    /// (as formatted by `MessageRecipients`'s `Display` impl, e.g.
+    /// `"#general"`), oldest first, for replaying into a freshly joined IRC
+    /// channel.
+    pub fn recent_for_recipient(
+        &self,
+        site: &str,
+        recipient: &str,
+        limit: usize,
+    ) -> rusqlite::Result<Vec<Message>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT payload FROM messages
+             WHERE site = ?1 AND recipient = ?2
+             ORDER BY message_id DESC
+             LIMIT ?3",
+        )?;
+        let mut messages: Vec<Message> = stmt
+            .query_map(rusqlite::params![site, recipient, limit], |row| {
+                let payload: String = row.get(0)?;
+                Ok(payload)
+            })?
+            .filter_map(|payload| serde_json::from_str(&payload.ok()?).ok())
+            .collect();
+        messages.reverse();
+        Ok(messages)
+    }
+}